@@ -0,0 +1,234 @@
+//! Register diversion checker.
+//!
+//! The coloring, spilling, and reload-removal passes rewrite the locations of values by emitting
+//! `regmove`, `regspill`, and `regfill` instructions and updating `RegDiversions` as they go. A
+//! bug in any of that bookkeeping - a wrong `from` in a move chain, a diversion that should have
+//! been dropped, a location read after the value has already moved on - normally only surfaces
+//! much later as a miscompile.
+//!
+//! The `Checker` in this module re-derives, symbolically, which `Value`s are expected to be
+//! found in every register and stack slot at every point in an EBB, and flags any instruction
+//! that reads or moves a value from a location that doesn't hold it. It is driven by the same events the allocator already
+//! produces (`ValueLocations` at EBB entry, plus the sequence of instructions in the EBB), so it
+//! can run as an optional verification pass over the allocator's own output, in tests or under
+//! fuzzing, without altering codegen.
+
+use ir::{Inst, InstructionData, Opcode, StackSlot, Value, ValueLoc, ValueLocations};
+use isa::RegUnit;
+use regalloc::diversion::RegDiversions;
+use std::collections::BTreeSet;
+use std::fmt;
+
+/// A location tracked by the checker: either a register unit or a stack slot.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+enum CheckerLoc {
+    Reg(RegUnit),
+    Stack(StackSlot),
+}
+
+impl From<ValueLoc> for CheckerLoc {
+    fn from(loc: ValueLoc) -> Self {
+        match loc {
+            ValueLoc::Reg(reg) => CheckerLoc::Reg(reg),
+            ValueLoc::Stack(ss) => CheckerLoc::Stack(ss),
+            ValueLoc::Unassigned => panic!("can't check an unassigned location"),
+        }
+    }
+}
+
+/// A violation of the expected dataflow, detected while checking a single instruction.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CheckerError {
+    /// `value` was read at `inst`, but the location the allocator claims it is in does not
+    /// contain `value` according to the symbolic tracking.
+    MissingValue { inst: Inst, value: Value },
+    /// A `regmove`/`regspill`/`regfill` tried to move `value` out of a location that, according
+    /// to the symbolic tracking, does not currently hold it.
+    BadMoveSource { inst: Inst, value: Value },
+}
+
+impl fmt::Display for CheckerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CheckerError::MissingValue { inst, value } => {
+                write!(f, "{}: {} not found in its claimed location", inst, value)
+            }
+            CheckerError::BadMoveSource { inst, value } => {
+                write!(f, "{}: {} is not in the location the move reads from", inst, value)
+            }
+        }
+    }
+}
+
+/// Symbolically tracks which values live in which locations, and checks that diversions are
+/// applied consistently with that knowledge.
+///
+/// A location can legitimately hold more than one `Value` at once (several names may alias the
+/// same storage after a copy), so each location maps to a *set* of values rather than a single
+/// one.
+pub struct Checker {
+    locations: Vec<(CheckerLoc, BTreeSet<Value>)>,
+}
+
+impl Checker {
+    /// Create a checker seeded from the allocator's `ValueLocations` at EBB entry.
+    pub fn new(locations: &ValueLocations) -> Self {
+        let mut checker = Self { locations: Vec::new() };
+        for (value, loc) in locations.iter() {
+            if loc.is_assigned() {
+                checker.define(CheckerLoc::from(*loc), value);
+            }
+        }
+        checker
+    }
+
+    fn values_at(&self, loc: CheckerLoc) -> Option<&BTreeSet<Value>> {
+        self.locations.iter().find(|&&(l, _)| l == loc).map(
+            |&(_, ref set)| set,
+        )
+    }
+
+    fn define(&mut self, loc: CheckerLoc, value: Value) {
+        if let Some(&mut (_, ref mut set)) =
+            self.locations.iter_mut().find(|&&mut (l, _)| l == loc)
+        {
+            set.insert(value);
+            return;
+        }
+        let mut set = BTreeSet::new();
+        set.insert(value);
+        self.locations.push((loc, set));
+    }
+
+    /// Forget that `loc` holds any values; it is about to be redefined.
+    fn clear(&mut self, loc: CheckerLoc) {
+        self.locations.retain(|&(l, _)| l != loc);
+    }
+
+    /// Forget that `value` resides at `loc`, without disturbing any other value that may still
+    /// alias the same location.
+    fn forget(&mut self, loc: CheckerLoc, value: Value) {
+        if let Some(&mut (_, ref mut set)) =
+            self.locations.iter_mut().find(|&&mut (l, _)| l == loc)
+        {
+            set.remove(&value);
+        }
+    }
+
+    fn holds(&self, loc: CheckerLoc, value: Value) -> bool {
+        self.values_at(loc).map_or(false, |set| set.contains(&value))
+    }
+
+    /// Check and apply the effect of a `regmove`/`regspill`/`regfill` that moves `value` from
+    /// `from` to `to`.
+    fn check_move(
+        &mut self,
+        inst: Inst,
+        value: Value,
+        from: CheckerLoc,
+        to: CheckerLoc,
+    ) -> Result<(), CheckerError> {
+        if !self.holds(from, value) {
+            return Err(CheckerError::BadMoveSource { inst, value });
+        }
+        // The value now lives at `to` and no longer at `from`: a location read after this move
+        // must see it at its new home, not its old one.
+        self.forget(from, value);
+        self.clear(to);
+        self.define(to, value);
+        Ok(())
+    }
+
+    /// Check a single instruction against the symbolic state, and update the state to reflect
+    /// its effect.
+    ///
+    /// `args` are the values the instruction reads, each looked up at its current (post-diversion)
+    /// location through `divert` and `locations`; `results` are the values it defines, each
+    /// recorded at the location `locations` assigns to it.
+    pub fn check_inst(
+        &mut self,
+        inst: Inst,
+        data: &InstructionData,
+        divert: &RegDiversions,
+        locations: &ValueLocations,
+        args: &[Value],
+        results: &[Value],
+    ) -> Result<(), CheckerError> {
+        match *data {
+            InstructionData::RegMove {
+                opcode: Opcode::Regmove,
+                arg,
+                src,
+                dst,
+            } => {
+                self.check_move(inst, arg, CheckerLoc::Reg(src), CheckerLoc::Reg(dst))?;
+            }
+            InstructionData::RegSpill {
+                opcode: Opcode::Regspill,
+                arg,
+                src,
+                dst,
+            } => {
+                self.check_move(inst, arg, CheckerLoc::Reg(src), CheckerLoc::Stack(dst))?;
+            }
+            InstructionData::RegFill {
+                opcode: Opcode::Regfill,
+                arg,
+                src,
+                dst,
+            } => {
+                self.check_move(inst, arg, CheckerLoc::Stack(src), CheckerLoc::Reg(dst))?;
+            }
+            _ => {
+                for &arg in args {
+                    let loc = CheckerLoc::from(divert.get(arg, locations));
+                    if !self.holds(loc, arg) {
+                        return Err(CheckerError::MissingValue { inst, value: arg });
+                    }
+                }
+                for &result in results {
+                    let loc = CheckerLoc::from(locations[result]);
+                    self.clear(loc);
+                    self.define(loc, result);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entity::EntityRef;
+    use ir::{Inst, Value, ValueLocations};
+
+    #[test]
+    fn move_transfers_value_to_destination() {
+        let mut locations = ValueLocations::new();
+        let v1 = Value::new(1);
+        locations[v1] = ValueLoc::Reg(10);
+        let mut checker = Checker::new(&locations);
+        let inst = Inst::new(0);
+
+        checker
+            .check_move(inst, v1, CheckerLoc::Reg(10), CheckerLoc::Reg(12))
+            .expect("moving a value out of where it's tracked should succeed");
+
+        // The value must show up at its new location, and nowhere else - a location read after
+        // this move must not see it at its old home.
+        assert!(checker.holds(CheckerLoc::Reg(12), v1));
+        assert!(!checker.holds(CheckerLoc::Reg(10), v1));
+    }
+
+    #[test]
+    fn move_from_wrong_location_is_rejected() {
+        let locations = ValueLocations::new();
+        let mut checker = Checker::new(&locations);
+        let v1 = Value::new(1);
+        let inst = Inst::new(0);
+
+        let result = checker.check_move(inst, v1, CheckerLoc::Reg(10), CheckerLoc::Reg(12));
+        assert_eq!(result, Err(CheckerError::BadMoveSource { inst, value: v1 }));
+    }
+}
@@ -4,14 +4,17 @@
 //! Sometimes, it is necessary to move register values to a different register in order to satisfy
 //! instruction constraints.
 //!
-//! These register diversions are local to an EBB. No values can be diverted when entering a new
-//! EBB.
+//! These register diversions are local to an EBB. A diversion doesn't have to be undone before
+//! the EBB's terminator, though: `resolve_edge()` can compute the moves needed to reconcile a
+//! predecessor's diverted locations with whatever its successor expects, so a value can stay in a
+//! convenient register across a straight-line edge instead of always being restored to its
+//! canonical location first.
 
+use fx::FxHashMap;
 use ir::{InstructionData, Opcode};
 use ir::{StackSlot, Value, ValueLoc, ValueLocations};
 use isa::{RegInfo, RegUnit};
 use std::fmt;
-use std::vec::Vec;
 
 /// A diversion of a value from its original location to a new register or stack location.
 ///
@@ -38,20 +41,32 @@ impl Diversion {
     }
 }
 
+/// An opaque marker returned by `RegDiversions::checkpoint()`, identifying a point in the
+/// tracker's history that `rewind()` can later restore.
+#[derive(Clone, Copy, Debug)]
+pub struct DiversionSnapshot(usize);
+
 /// Keep track of diversions in an EBB.
 pub struct RegDiversions {
-    current: Vec<Diversion>,
+    current: FxHashMap<Value, Diversion>,
+    /// Append-only log of edits to `current`, recording the value touched and its prior entry
+    /// (`None` if it wasn't present). Used to support cheap `checkpoint()`/`rewind()`.
+    undo_log: Vec<(Value, Option<Diversion>)>,
 }
 
 impl RegDiversions {
     /// Create a new empty diversion tracker.
     pub fn new() -> Self {
-        Self { current: Vec::new() }
+        Self {
+            current: FxHashMap::default(),
+            undo_log: Vec::new(),
+        }
     }
 
     /// Clear the tracker, preparing for a new EBB.
     pub fn clear(&mut self) {
-        self.current.clear()
+        self.current.clear();
+        self.undo_log.clear();
     }
 
     /// Are there any diversions?
@@ -61,12 +76,12 @@ impl RegDiversions {
 
     /// Get the current diversion of `value`, if any.
     pub fn diversion(&self, value: Value) -> Option<&Diversion> {
-        self.current.iter().find(|d| d.value == value)
+        self.current.get(&value)
     }
 
-    /// Get all current diversions.
-    pub fn all(&self) -> &[Diversion] {
-        self.current.as_slice()
+    /// Get an iterator over all current diversions, in unspecified order.
+    pub fn iter<'a>(&'a self) -> impl Iterator<Item = (Value, &'a Diversion)> {
+        self.current.iter().map(|(&value, d)| (value, d))
     }
 
     /// Get the current location for `value`. Fall back to the assignment map for non-diverted
@@ -92,16 +107,23 @@ impl RegDiversions {
     ///
     /// The `from` location must match an existing `to` location, if any.
     pub fn divert(&mut self, value: Value, from: ValueLoc, to: ValueLoc) {
+        use std::collections::hash_map::Entry::*;
         debug_assert!(from.is_assigned() && to.is_assigned());
-        if let Some(i) = self.current.iter().position(|d| d.value == value) {
-            debug_assert_eq!(self.current[i].to, from, "Bad regmove chain for {}", value);
-            if self.current[i].from != to {
-                self.current[i].to = to;
-            } else {
-                self.current.swap_remove(i);
+        match self.current.entry(value) {
+            Occupied(mut e) => {
+                debug_assert_eq!(e.get().to, from, "Bad regmove chain for {}", value);
+                let prev = *e.get();
+                if e.get().from != to {
+                    e.get_mut().to = to;
+                } else {
+                    e.remove();
+                }
+                self.undo_log.push((value, Some(prev)));
+            }
+            Vacant(e) => {
+                e.insert(Diversion::new(value, from, to));
+                self.undo_log.push((value, None));
             }
-        } else {
-            self.current.push(Diversion::new(value, from, to));
         }
     }
 
@@ -152,11 +174,130 @@ impl RegDiversions {
     ///
     /// Returns the `to` location of the removed diversion.
     pub fn remove(&mut self, value: Value) -> Option<ValueLoc> {
-        self.current.iter().position(|d| d.value == value).map(
-            |i| {
-                self.current.swap_remove(i).to
-            },
-        )
+        match self.current.remove(&value) {
+            Some(d) => {
+                self.undo_log.push((value, Some(d)));
+                Some(d.to)
+            }
+            None => None,
+        }
+    }
+
+    /// Record the current state of the tracker, for a later `rewind()`.
+    ///
+    /// This is cheap: it just remembers how far along the undo log we are, so a solver can try
+    /// out a candidate set of register moves for an instruction and roll back without
+    /// re-deriving the pre-instruction location map from scratch.
+    pub fn checkpoint(&self) -> DiversionSnapshot {
+        DiversionSnapshot(self.undo_log.len())
+    }
+
+    /// Undo every `divert`/`remove` recorded since `snapshot` was taken, restoring the tracker to
+    /// exactly the state it was in at that point.
+    pub fn rewind(&mut self, snapshot: DiversionSnapshot) {
+        while self.undo_log.len() > snapshot.0 {
+            let (value, prev) = self.undo_log.pop().expect("checked non-empty above");
+            match prev {
+                Some(d) => {
+                    self.current.insert(value, d);
+                }
+                None => {
+                    self.current.remove(&value);
+                }
+            }
+        }
+    }
+
+    /// Compute the moves needed to reconcile the current locations of `live` values with the
+    /// locations the successor EBB expects for them (`expected`), given that `locations` is the
+    /// allocator's canonical assignment used to look up values that aren't currently diverted.
+    ///
+    /// Returns the minimal set of register->register, register->stack, and stack->register moves
+    /// needed on the edge, in an order that is safe to emit as-is: a move never overwrites a
+    /// location that a later move in the returned list still has to read. Every
+    /// returned `Diversion` can be materialized directly as a `regmove`/`regspill`/`regfill`
+    /// instruction: since there's no stack-to-stack move instruction, a stack->stack
+    /// reconciliation is expanded into a stack->`scratch_reg` regfill followed by a
+    /// `scratch_reg`->stack regspill.
+    ///
+    /// Cycles (`a` needs to go where `b` currently is, and vice versa) are broken by parking one
+    /// value in `scratch_slot` - a stack slot, not `scratch_reg` - while the rest of the cycle is
+    /// resolved. That matters because resolving the rest of the cycle may itself need to expand a
+    /// stack->stack move through `scratch_reg`: if the parked value lived in `scratch_reg` too,
+    /// that expansion would clobber it. Parking in a stack slot instead leaves `scratch_reg` free
+    /// for those transient relays; it is only ever borrowed for the lifetime of a single move.
+    ///
+    /// `scratch_reg` must name a register, and `scratch_slot` a stack slot, that are both free on
+    /// this edge.
+    pub fn resolve_edge<I>(
+        &self,
+        locations: &ValueLocations,
+        expected: &ValueLocations,
+        live: I,
+        scratch_reg: RegUnit,
+        scratch_slot: StackSlot,
+    ) -> Vec<Diversion>
+    where
+        I: Iterator<Item = Value>,
+    {
+        // Moves still left to perform, as (value, current location, wanted location).
+        let mut pending: Vec<(Value, ValueLoc, ValueLoc)> = live.filter_map(|value| {
+            let from = self.get(value, locations);
+            let to = expected[value];
+            if from != to { Some((value, from, to)) } else { None }
+        }).collect();
+
+        let mut resolved = Vec::with_capacity(pending.len());
+
+        while !pending.is_empty() {
+            // A move is safe to emit now if nothing else still pending needs to read the
+            // location it's about to overwrite.
+            let ready = pending.iter().position(|&(_, _, to)| {
+                !pending.iter().any(|&(_, from, _)| from == to)
+            });
+
+            if let Some(i) = ready {
+                let (value, from, to) = pending.remove(i);
+                Self::push_move(&mut resolved, value, from, to, scratch_reg);
+            } else {
+                // Everything left is part of a cycle. Break it by parking one value in
+                // `scratch_slot`: anything waiting to read its old location reads `scratch_slot`
+                // instead, and its own move to its final destination re-joins the pending list
+                // (where it becomes ready once the rest of the cycle has vacated that destination).
+                let (value, from, to) = pending.remove(0);
+                let via = ValueLoc::Stack(scratch_slot);
+                Self::push_move(&mut resolved, value, from, via, scratch_reg);
+                for entry in pending.iter_mut() {
+                    if entry.1 == from {
+                        entry.1 = via;
+                    }
+                }
+                pending.push((value, via, to));
+            }
+        }
+
+        resolved
+    }
+
+    /// Push the move of `value` from `from` to `to` onto `resolved`, routing it through
+    /// `scratch_reg` if it would otherwise have to be a stack->stack move (which no instruction
+    /// can perform directly). `scratch_reg` is only ever touched transiently here, for the
+    /// duration of a single move, so it's safe to call even while another value sits parked in a
+    /// `scratch_slot` from a cycle break.
+    fn push_move(
+        resolved: &mut Vec<Diversion>,
+        value: Value,
+        from: ValueLoc,
+        to: ValueLoc,
+        scratch_reg: RegUnit,
+    ) {
+        if let (ValueLoc::Stack(_), ValueLoc::Stack(_)) = (from, to) {
+            let via = ValueLoc::Reg(scratch_reg);
+            resolved.push(Diversion::new(value, from, via));
+            resolved.push(Diversion::new(value, via, to));
+        } else {
+            resolved.push(Diversion::new(value, from, to));
+        }
     }
 
     /// Return an object that can display the diversions.
@@ -171,11 +312,16 @@ pub struct DisplayDiversions<'a>(&'a RegDiversions, Option<&'a RegInfo>);
 impl<'a> fmt::Display for DisplayDiversions<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(f, "{{")?;
-        for div in self.0.all() {
+        // `RegDiversions` is backed by a hash map, whose iteration order is not stable from one
+        // run to the next; sort by `Value` so that textual dumps (e.g. filetest output) stay
+        // reproducible.
+        let mut diversions: Vec<_> = self.0.iter().collect();
+        diversions.sort_by_key(|&(value, _)| value);
+        for (value, div) in diversions {
             write!(
                 f,
                 " {}: {} -> {}",
-                div.value,
+                value,
                 div.from.display(self.1),
                 div.to.display(self.1)
             )?
@@ -212,4 +358,168 @@ mod tests {
         divs.regmove(v1, 11, 10);
         assert_eq!(divs.diversion(v1), None);
     }
+
+    #[test]
+    fn rewind_undoes_mutation() {
+        let mut divs = RegDiversions::new();
+        let v1 = Value::new(1);
+
+        divs.regmove(v1, 10, 12);
+        let snap = divs.checkpoint();
+        divs.regmove(v1, 12, 14);
+        assert_eq!(divs.diversion(v1).unwrap().to, ValueLoc::Reg(14));
+
+        divs.rewind(snap);
+        assert_eq!(divs.diversion(v1).unwrap().to, ValueLoc::Reg(12));
+    }
+
+    #[test]
+    fn rewind_undoes_identity_remove() {
+        let mut divs = RegDiversions::new();
+        let v1 = Value::new(1);
+
+        divs.regmove(v1, 10, 12);
+        let snap = divs.checkpoint();
+        // This regmove takes v1 back to its original location, which hits the identity
+        // fast-path in divert() and removes the diversion entirely.
+        divs.regmove(v1, 12, 10);
+        assert_eq!(divs.diversion(v1), None);
+
+        divs.rewind(snap);
+        assert_eq!(divs.diversion(v1).unwrap().to, ValueLoc::Reg(12));
+    }
+
+    #[test]
+    fn rewind_undoes_insert() {
+        let mut divs = RegDiversions::new();
+        let v1 = Value::new(1);
+
+        let snap = divs.checkpoint();
+        divs.regmove(v1, 10, 12);
+        assert!(divs.diversion(v1).is_some());
+
+        divs.rewind(snap);
+        assert_eq!(divs.diversion(v1), None);
+    }
+
+    #[test]
+    fn resolve_edge_single_move() {
+        let mut locations = ValueLocations::new();
+        let mut expected = ValueLocations::new();
+        let v1 = Value::new(1);
+        locations[v1] = ValueLoc::Reg(10);
+        expected[v1] = ValueLoc::Reg(11);
+
+        let divs = RegDiversions::new();
+        let scratch_slot = StackSlot::new(9);
+        let moves =
+            divs.resolve_edge(&locations, &expected, vec![v1].into_iter(), 99, scratch_slot);
+        assert_eq!(
+            moves,
+            vec![Diversion::new(v1, ValueLoc::Reg(10), ValueLoc::Reg(11))]
+        );
+    }
+
+    #[test]
+    fn resolve_edge_breaks_register_cycles() {
+        let mut locations = ValueLocations::new();
+        let mut expected = ValueLocations::new();
+        let v1 = Value::new(1);
+        let v2 = Value::new(2);
+        locations[v1] = ValueLoc::Reg(10);
+        locations[v2] = ValueLoc::Reg(11);
+        expected[v1] = ValueLoc::Reg(11);
+        expected[v2] = ValueLoc::Reg(10);
+
+        let scratch_reg = 99;
+        let scratch_slot = StackSlot::new(9);
+        let divs = RegDiversions::new();
+        let moves = divs.resolve_edge(
+            &locations,
+            &expected,
+            vec![v1, v2].into_iter(),
+            scratch_reg,
+            scratch_slot,
+        );
+        // v1 is parked on the stack so v2 can take its old register, then v1 lands in v2's old
+        // register.
+        assert_eq!(
+            moves,
+            vec![
+                Diversion::new(v1, ValueLoc::Reg(10), ValueLoc::Stack(scratch_slot)),
+                Diversion::new(v2, ValueLoc::Reg(11), ValueLoc::Reg(10)),
+                Diversion::new(v1, ValueLoc::Stack(scratch_slot), ValueLoc::Reg(11)),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_edge_stack_to_stack_via_scratch() {
+        let mut locations = ValueLocations::new();
+        let mut expected = ValueLocations::new();
+        let v1 = Value::new(1);
+        let ss0 = StackSlot::new(0);
+        let ss1 = StackSlot::new(1);
+        locations[v1] = ValueLoc::Stack(ss0);
+        expected[v1] = ValueLoc::Stack(ss1);
+
+        let scratch_reg = 7;
+        let scratch_slot = StackSlot::new(9);
+        let divs = RegDiversions::new();
+        let moves = divs.resolve_edge(
+            &locations,
+            &expected,
+            vec![v1].into_iter(),
+            scratch_reg,
+            scratch_slot,
+        );
+        // There's no stack-to-stack move instruction, so this must go through the scratch reg.
+        assert_eq!(
+            moves,
+            vec![
+                Diversion::new(v1, ValueLoc::Stack(ss0), ValueLoc::Reg(scratch_reg)),
+                Diversion::new(v1, ValueLoc::Reg(scratch_reg), ValueLoc::Stack(ss1)),
+            ]
+        );
+    }
+
+    #[test]
+    fn resolve_edge_breaks_stack_cycles_without_clobbering_the_parked_value() {
+        // A pure stack<->stack cycle is the case that previously clobbered the parked value:
+        // resolving v2's leg is itself a stack->stack move, and if v1 were parked in the same
+        // scratch register that move would need, v1's bits would be destroyed before its final
+        // move out of the register.
+        let mut locations = ValueLocations::new();
+        let mut expected = ValueLocations::new();
+        let v1 = Value::new(1);
+        let v2 = Value::new(2);
+        let ss0 = StackSlot::new(0);
+        let ss1 = StackSlot::new(1);
+        locations[v1] = ValueLoc::Stack(ss0);
+        locations[v2] = ValueLoc::Stack(ss1);
+        expected[v1] = ValueLoc::Stack(ss1);
+        expected[v2] = ValueLoc::Stack(ss0);
+
+        let scratch_reg = 7;
+        let scratch_slot = StackSlot::new(9);
+        let divs = RegDiversions::new();
+        let moves = divs.resolve_edge(
+            &locations,
+            &expected,
+            vec![v1, v2].into_iter(),
+            scratch_reg,
+            scratch_slot,
+        );
+        assert_eq!(
+            moves,
+            vec![
+                Diversion::new(v1, ValueLoc::Stack(ss0), ValueLoc::Reg(scratch_reg)),
+                Diversion::new(v1, ValueLoc::Reg(scratch_reg), ValueLoc::Stack(scratch_slot)),
+                Diversion::new(v2, ValueLoc::Stack(ss1), ValueLoc::Reg(scratch_reg)),
+                Diversion::new(v2, ValueLoc::Reg(scratch_reg), ValueLoc::Stack(ss0)),
+                Diversion::new(v1, ValueLoc::Stack(scratch_slot), ValueLoc::Reg(scratch_reg)),
+                Diversion::new(v1, ValueLoc::Reg(scratch_reg), ValueLoc::Stack(ss1)),
+            ]
+        );
+    }
 }
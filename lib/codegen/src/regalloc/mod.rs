@@ -0,0 +1,7 @@
+//! Register allocation.
+//!
+//! This module contains data structures and algorithms used for register allocation.
+
+pub mod checker;
+pub mod diversion;
+pub mod redundant_reload_remover;
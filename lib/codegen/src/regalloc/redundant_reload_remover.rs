@@ -0,0 +1,336 @@
+//! Redundant reload removal.
+//!
+//! After register allocation has assigned every value a register or a stack slot, a `fill`
+//! instruction may reload a value into a register even though some other register in the same
+//! EBB already holds an identical copy of it. This pass walks each EBB once, right after
+//! `spilling` and `coloring` have run, and deletes those redundant fills.
+//!
+//! The pass tracks, for each stack slot, which register (if any) is known to currently hold the
+//! same value as that slot. A `spill` establishes such an equality; a `fill` either satisfies
+//! itself from an already-equal register (and is deleted) or establishes a fresh equality.
+//! Redefining a register, or overwriting a stack slot, invalidates the equalities that mention
+//! it.
+//!
+//! Deleting a fill can't just rewrite its result's canonical location to the register that
+//! already holds the value: the allocator reserved that register exclusively for the *original*
+//! occupant's live range, which may end well before the fill's result is done being used, and
+//! nothing would stop the allocator from handing the register to a later value in the meantime.
+//! Instead, a deleted fill's result is diverted into the register through `RegDiversions` -
+//! scoped to this EBB, like every other diversion - and only after checking that the register is
+//! not redefined again before the result's own last use in the EBB.
+//!
+//! Availability never survives an EBB boundary: diversions, and therefore the equalities this
+//! pass relies on, are local to a single EBB.
+
+use cursor::{Cursor, EncCursor};
+use fx::FxHashMap;
+use ir::{Ebb, InstructionData, Opcode, StackSlot, Value, ValueLoc};
+use isa::{RegUnit, TargetIsa};
+use regalloc::diversion::RegDiversions;
+
+/// Per-EBB facts needed to prove that diverting a deleted fill's result into the register that
+/// already holds an equal value won't outlive that register's next redefinition.
+///
+/// Built once per EBB from its original instruction stream, before any fills in it are removed.
+struct EbbReloadPlan {
+    /// The position, within the EBB's instruction order, of each value's last use as an
+    /// instruction argument (including branch/jump arguments).
+    last_use: FxHashMap<Value, usize>,
+    /// For each register, the positions at which it is redefined: either an instruction result
+    /// colored into it, or a `regmove`/`regfill` that retargets it.
+    redefines: FxHashMap<RegUnit, Vec<usize>>,
+}
+
+impl EbbReloadPlan {
+    fn build(func: &::ir::Function, ebb: Ebb) -> Self {
+        let mut last_use = FxHashMap::default();
+        let mut redefines: FxHashMap<RegUnit, Vec<usize>> = FxHashMap::default();
+
+        for (pos, inst) in func.layout.ebb_insts(ebb).enumerate() {
+            for &arg in func.dfg.inst_args(inst) {
+                last_use.insert(arg, pos);
+            }
+            match func.dfg[inst] {
+                InstructionData::RegMove { dst, .. } |
+                InstructionData::RegFill { dst, .. } => {
+                    redefines.entry(dst).or_insert_with(Vec::new).push(pos);
+                }
+                _ => {}
+            }
+            for &result in func.dfg.inst_results(inst) {
+                if let ValueLoc::Reg(reg) = func.locations[result] {
+                    redefines.entry(reg).or_insert_with(Vec::new).push(pos);
+                }
+            }
+        }
+
+        Self { last_use, redefines }
+    }
+
+    /// Returns true if, from just after `from_pos` through `value`'s last use in the EBB, `reg`
+    /// is never redefined - i.e. it's safe to keep reading `value` out of `reg` for that whole
+    /// span.
+    fn reg_survives_until_last_use(&self, reg: RegUnit, from_pos: usize, value: Value) -> bool {
+        let last = match self.last_use.get(&value) {
+            Some(&pos) => pos,
+            // Not used again within this EBB: its live range may extend into a successor, which
+            // this pass has no visibility into, so diverting it isn't provably safe.
+            None => return false,
+        };
+        match self.redefines.get(&reg) {
+            Some(positions) => !positions.iter().any(|&p| p > from_pos && p <= last),
+            None => true,
+        }
+    }
+}
+
+/// Removes fills that reload a value already resident in a register.
+pub struct RedundantReloadRemover {
+    /// For every stack slot that is currently known to hold the same value as a register, the
+    /// register that holds it.
+    available: FxHashMap<StackSlot, RegUnit>,
+    /// The inverse of `available`, used to invalidate an entry when its register is redefined.
+    holds: FxHashMap<RegUnit, StackSlot>,
+}
+
+impl RedundantReloadRemover {
+    /// Create a new, empty redundant reload remover.
+    pub fn new() -> Self {
+        Self {
+            available: FxHashMap::default(),
+            holds: FxHashMap::default(),
+        }
+    }
+
+    /// Clear the availability table, preparing for a new EBB.
+    fn clear(&mut self) {
+        self.available.clear();
+        self.holds.clear();
+    }
+
+    /// Drop any equality that mentions `reg`, because it is about to be redefined.
+    fn invalidate_reg(&mut self, reg: RegUnit) {
+        if let Some(slot) = self.holds.remove(&reg) {
+            self.available.remove(&slot);
+        }
+    }
+
+    /// Drop any equality that mentions `slot`, because it is about to be overwritten.
+    fn invalidate_slot(&mut self, slot: StackSlot) {
+        if let Some(reg) = self.available.remove(&slot) {
+            self.holds.remove(&reg);
+        }
+    }
+
+    /// Record that `slot` and `reg` currently hold the same value.
+    fn set_available(&mut self, slot: StackSlot, reg: RegUnit) {
+        self.invalidate_slot(slot);
+        self.invalidate_reg(reg);
+        self.available.insert(slot, reg);
+        self.holds.insert(reg, slot);
+    }
+
+    /// Remove redundant reloads from `func`. The registers an instruction's results are written
+    /// to are taken from `func.locations`; `isa` is only used to build the cursor.
+    pub fn run(&mut self, isa: &TargetIsa, func: &mut ::ir::Function) {
+        let mut divert = RegDiversions::new();
+        let mut pos = EncCursor::new(func, isa);
+
+        while let Some(ebb) = pos.next_ebb() {
+            self.clear();
+            divert.clear();
+
+            let reload_plan = EbbReloadPlan::build(pos.func, ebb);
+            let mut ebb_pos = 0;
+
+            while let Some(inst) = pos.next_inst() {
+                let this_pos = ebb_pos;
+                ebb_pos += 1;
+
+                match pos.func.dfg[inst] {
+                    InstructionData::Unary {
+                        opcode: Opcode::Spill,
+                        arg,
+                    } => {
+                        let slot = pos.func.locations[pos.func.dfg.first_result(inst)]
+                            .unwrap_stack();
+                        let reg = divert.reg(arg, &pos.func.locations);
+                        self.set_available(slot, reg);
+                    }
+                    InstructionData::Unary {
+                        opcode: Opcode::Fill,
+                        arg,
+                    } => {
+                        let slot = divert.stack(arg, &pos.func.locations);
+                        let result = pos.func.dfg.first_result(inst);
+                        let dst = pos.func.locations[result].unwrap_reg();
+                        let available = self.available.get(&slot).cloned();
+                        match available {
+                            Some(reg)
+                                if reload_plan.reg_survives_until_last_use(
+                                    reg,
+                                    this_pos,
+                                    result,
+                                ) =>
+                            {
+                                // `reg` already holds the same value, and is provably not
+                                // redefined before `result`'s last use in this EBB: divert
+                                // `result` into `reg` through the same block-local mechanism
+                                // every other recoloring uses, rather than rewriting its
+                                // canonical location, and drop the fill.
+                                divert.regmove(result, dst, reg);
+                                pos.remove_inst_and_step_back();
+                            }
+                            _ => self.set_available(slot, dst),
+                        }
+                    }
+                    InstructionData::RegMove {
+                        opcode: Opcode::Regmove,
+                        dst,
+                        ..
+                    } => {
+                        divert.apply(&pos.func.dfg[inst]);
+                        self.invalidate_reg(dst);
+                    }
+                    InstructionData::RegSpill {
+                        opcode: Opcode::Regspill,
+                        dst,
+                        ..
+                    } => {
+                        divert.apply(&pos.func.dfg[inst]);
+                        self.invalidate_slot(dst);
+                    }
+                    InstructionData::RegFill {
+                        opcode: Opcode::Regfill,
+                        dst,
+                        ..
+                    } => {
+                        divert.apply(&pos.func.dfg[inst]);
+                        self.invalidate_reg(dst);
+                    }
+                    ref data => {
+                        let opcode = data.opcode();
+                        divert.apply(data);
+
+                        if opcode.is_call() {
+                            // A call clobbers every caller-saved register per the ABI; rather
+                            // than special-case the clobber set here, conservatively drop every
+                            // equality we know about.
+                            self.clear();
+                        } else {
+                            // Any register clobbered by this instruction's defs can no longer be
+                            // assumed equal to the stack slot it used to shadow.
+                            for &res in pos.func.dfg.inst_results(inst) {
+                                if let ValueLoc::Reg(reg) = pos.func.locations[res] {
+                                    self.invalidate_reg(reg);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use entity::EntityRef;
+
+    #[test]
+    fn set_available_records_the_equality() {
+        let mut remover = RedundantReloadRemover::new();
+        let ss0 = StackSlot::new(0);
+        remover.set_available(ss0, 10);
+        assert_eq!(remover.available.get(&ss0), Some(&10));
+        assert_eq!(remover.holds.get(&10), Some(&ss0));
+    }
+
+    #[test]
+    fn invalidate_reg_drops_the_slot_it_was_backing() {
+        let mut remover = RedundantReloadRemover::new();
+        let ss0 = StackSlot::new(0);
+        remover.set_available(ss0, 10);
+
+        remover.invalidate_reg(10);
+
+        assert_eq!(remover.available.get(&ss0), None);
+        assert_eq!(remover.holds.get(&10), None);
+    }
+
+    #[test]
+    fn invalidate_slot_drops_the_register_it_was_backed_by() {
+        let mut remover = RedundantReloadRemover::new();
+        let ss0 = StackSlot::new(0);
+        remover.set_available(ss0, 10);
+
+        remover.invalidate_slot(ss0);
+
+        assert_eq!(remover.available.get(&ss0), None);
+        assert_eq!(remover.holds.get(&10), None);
+    }
+
+    #[test]
+    fn set_available_overwrites_a_stale_equality() {
+        let mut remover = RedundantReloadRemover::new();
+        let ss0 = StackSlot::new(0);
+        remover.set_available(ss0, 10);
+
+        // ss0 now holds the same value as register 11 instead; the old equality with register 10
+        // must not linger.
+        remover.set_available(ss0, 11);
+
+        assert_eq!(remover.available.get(&ss0), Some(&11));
+        assert_eq!(remover.holds.get(&10), None);
+    }
+
+    #[test]
+    fn reg_survives_until_last_use_when_never_redefined() {
+        let mut last_use = FxHashMap::default();
+        let v0 = Value::new(0);
+        last_use.insert(v0, 5);
+        let plan = EbbReloadPlan {
+            last_use,
+            redefines: FxHashMap::default(),
+        };
+
+        assert!(plan.reg_survives_until_last_use(10, 1, v0));
+    }
+
+    #[test]
+    fn reg_survives_until_last_use_rejects_a_redefinition_before_the_last_use() {
+        let mut last_use = FxHashMap::default();
+        let v0 = Value::new(0);
+        last_use.insert(v0, 5);
+        let mut redefines = FxHashMap::default();
+        redefines.insert(10, vec![3]);
+        let plan = EbbReloadPlan { last_use, redefines };
+
+        assert!(!plan.reg_survives_until_last_use(10, 1, v0));
+    }
+
+    #[test]
+    fn reg_survives_until_last_use_ignores_a_redefinition_at_or_before_from_pos() {
+        let mut last_use = FxHashMap::default();
+        let v0 = Value::new(0);
+        last_use.insert(v0, 5);
+        let mut redefines = FxHashMap::default();
+        // The redefinition at position 1 is the fill's own result, not a later clobber: it must
+        // not count against a move starting right after it.
+        redefines.insert(10, vec![1]);
+        let plan = EbbReloadPlan { last_use, redefines };
+
+        assert!(plan.reg_survives_until_last_use(10, 1, v0));
+    }
+
+    #[test]
+    fn reg_survives_until_last_use_rejects_a_value_not_used_again_in_the_ebb() {
+        let plan = EbbReloadPlan {
+            last_use: FxHashMap::default(),
+            redefines: FxHashMap::default(),
+        };
+
+        assert!(!plan.reg_survives_until_last_use(10, 1, Value::new(0)));
+    }
+}